@@ -14,11 +14,29 @@ extern "C" {
     pub fn ov_read_float(vf: *mut OggVorbis_File, pcm_channels: *mut *mut *mut c_float,
                          samples: c_int, bitstream: *mut c_int) -> c_long;
 
+    pub fn ov_read(vf: *mut OggVorbis_File, buffer: *mut c_char, length: c_int,
+                   bigendianp: c_int, word: c_int, sgned: c_int,
+                   bitstream: *mut c_int) -> c_long;
+
     pub fn ov_info(vf: *mut OggVorbis_File, link: c_int) -> *const vorbis_info;
 
     pub fn ov_clear(vf: *mut OggVorbis_File) -> c_int;
 
     pub fn ov_comment(vf: *mut OggVorbis_File, link: c_int) -> *const vorbis_comment;
+
+    pub fn ov_pcm_seek(vf: *mut OggVorbis_File, pos: i64) -> c_int;
+
+    pub fn ov_time_seek(vf: *mut OggVorbis_File, pos: c_double) -> c_int;
+
+    pub fn ov_seekable(vf: *mut OggVorbis_File) -> c_long;
+
+    pub fn ov_pcm_total(vf: *mut OggVorbis_File, link: c_int) -> i64;
+
+    pub fn ov_time_total(vf: *mut OggVorbis_File, link: c_int) -> c_double;
+
+    pub fn ov_bitrate(vf: *mut OggVorbis_File, link: c_int) -> c_long;
+
+    pub fn ov_bitrate_instant(vf: *mut OggVorbis_File) -> c_long;
 }
 
 pub static OV_FALSE: c_int = -1;
@@ -6,12 +6,13 @@
 //! Ogg Vorbis file decoding, library bindings.
 
 extern crate libc;
-use libc::{c_void, c_int, c_long, size_t};
+use libc::{c_void, c_int, c_long, c_double, c_char, size_t};
 
+use std::ascii::AsciiExt;
 use std::error::Error;
 use std::ffi::CStr;
 use std::fmt;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
 use std::mem;
 use std::str;
 use std::ptr;
@@ -53,6 +54,13 @@ pub enum OVError {
     CorruptLink,
     /// The stream is not seekable.
     NotSeekable,
+    /// The requested value isn't available yet (e.g. instantaneous bitrate
+    /// before enough data has been decoded).
+    Unavailable,
+    /// The bitstream does not contain any audio data.
+    NotAudio,
+    /// Encountered a corrupt or malformed packet.
+    BadPacket,
 }
 
 impl Error for OVError {
@@ -69,6 +77,9 @@ impl Error for OVError {
             OVError::UnsupportedVersion => "Bitstream format revision not supported",
             OVError::CorruptLink => "Vorbis link is corrupt",
             OVError::NotSeekable => "Not seekable",
+            OVError::Unavailable => "Value not available yet",
+            OVError::NotAudio => "Bitstream does not contain audio data",
+            OVError::BadPacket => "Corrupt or malformed packet",
         }
     }
 }
@@ -94,6 +105,9 @@ impl OVError {
             ffi::OV_EVERSION => OVError::UnsupportedVersion,
             ffi::OV_EBADLINK => OVError::CorruptLink,
             ffi::OV_ENOSEEK => OVError::NotSeekable,
+            ffi::OV_FALSE => OVError::Unavailable,
+            ffi::OV_ENOTAUDIO => OVError::NotAudio,
+            ffi::OV_EBADPACKET => OVError::BadPacket,
             x => panic!("Unexpected OVError code: {}", x)
         }
     }
@@ -105,6 +119,49 @@ pub struct VorbisFile<R: Read> {
     decoder: ffi::OggVorbis_File,
     // Totally not 'static, but need a lifetime specifier to get a slice.
     channels: Vec<raw::Slice<f32>>,
+    // Reusable output buffer for decode_pcm.
+    pcm_buffer: Vec<u8>,
+}
+
+/// Interleaved PCM sample format for `VorbisFile::decode_pcm`.
+#[derive(Debug, Clone, Copy)]
+pub struct PcmFormat {
+    /// Whether output samples are big-endian.
+    pub bigendian: bool,
+    /// Bytes per sample: 1 or 2.
+    pub word: u8,
+    /// Whether output samples are signed.
+    pub signed: bool,
+}
+
+impl PcmFormat {
+    /// Interleaved signed 16-bit PCM, native endianness.
+    ///
+    /// The format most PCM sinks (ALSA, MPD's output path) expect.
+    pub fn s16_native() -> PcmFormat {
+        PcmFormat {
+            bigendian: cfg!(target_endian = "big"),
+            word: 2,
+            signed: true,
+        }
+    }
+}
+
+/// Structured Vorbis stream info: sample rate, channel count, and bitrate.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamInfo {
+    /// Vorbis bitstream format version.
+    pub version: i32,
+    /// Number of audio channels.
+    pub channels: i32,
+    /// Sample rate, in Hz.
+    pub rate: i64,
+    /// Upper bound on nominal bitrate, or -1 if unset.
+    pub bitrate_upper: i64,
+    /// Nominal (average, target) bitrate, or -1 if unset.
+    pub bitrate_nominal: i64,
+    /// Lower bound on nominal bitrate, or -1 if unset.
+    pub bitrate_lower: i64,
 }
 
 /// File metadata
@@ -115,6 +172,101 @@ pub struct Comments<'a> {
     pub comments: Vec<&'a str>
 }
 
+impl<'a> Comments<'a> {
+    /// Parse the raw `KEY=VALUE` comments into a queryable tag map.
+    pub fn tags(&self) -> Tags<'a> {
+        Tags::new(&self.comments)
+    }
+}
+
+/// A parsed, queryable view over a `Comments`' raw `KEY=VALUE` entries.
+pub struct Tags<'a> {
+    entries: Vec<(String, &'a str)>
+}
+
+impl<'a> Tags<'a> {
+    /// Parse a flat `KEY=VALUE` comment list into a tag map.
+    ///
+    /// Keys are upper-cased, since Vorbis comment keys are specified to be
+    /// case-insensitive ASCII. Entries without an `=` are ignored.
+    pub fn new(comments: &[&'a str]) -> Tags<'a> {
+        Tags {
+            entries: comments.iter().filter_map(|c| {
+                match c.find('=') {
+                    Some(i) => Some((c[..i].to_ascii_uppercase(), &c[i + 1..])),
+                    None => None
+                }
+            }).collect()
+        }
+    }
+
+    /// Get the first value for `key` (case-insensitive).
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        let key = key.to_ascii_uppercase();
+        self.entries.iter().find(|&&(ref k, _)| *k == key).map(|&(_, v)| v)
+    }
+
+    /// Get all values for `key` (case-insensitive); keys may repeat.
+    pub fn get_all(&self, key: &str) -> Vec<&'a str> {
+        let key = key.to_ascii_uppercase();
+        self.entries.iter().filter(|&&(ref k, _)| *k == key).map(|&(_, v)| v).collect()
+    }
+
+    /// The track title, if present.
+    pub fn title(&self) -> Option<&'a str> {
+        self.get("TITLE")
+    }
+
+    /// The track artist, if present.
+    pub fn artist(&self) -> Option<&'a str> {
+        self.get("ARTIST")
+    }
+
+    /// The album name, if present.
+    pub fn album(&self) -> Option<&'a str> {
+        self.get("ALBUM")
+    }
+
+    /// The track number, if present and parseable.
+    ///
+    /// Accepts both a bare number and the common `N/total` form.
+    pub fn track_number(&self) -> Option<u32> {
+        self.get("TRACKNUMBER").and_then(|v| v.trim().split('/').next())
+            .and_then(|n| n.parse().ok())
+    }
+
+    /// Parsed ReplayGain gain and peak values.
+    ///
+    /// Gain tags look like `-7.89 dB`; only the first whitespace-separated
+    /// token is parsed, so a unit glued to the number (`-7.89dB`) gives `None`.
+    pub fn replay_gain(&self) -> ReplayGain {
+        ReplayGain {
+            track_gain: self.gain_db("REPLAYGAIN_TRACK_GAIN"),
+            track_peak: self.get("REPLAYGAIN_TRACK_PEAK").and_then(|v| v.trim().parse().ok()),
+            album_gain: self.gain_db("REPLAYGAIN_ALBUM_GAIN"),
+            album_peak: self.get("REPLAYGAIN_ALBUM_PEAK").and_then(|v| v.trim().parse().ok()),
+        }
+    }
+
+    fn gain_db(&self, key: &str) -> Option<f32> {
+        self.get(key).and_then(|v| v.trim().split_whitespace().next())
+            .and_then(|n| n.parse().ok())
+    }
+}
+
+/// Parsed ReplayGain tags: gain in dB, peak on a linear scale.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplayGain {
+    /// Track gain, in dB.
+    pub track_gain: Option<f32>,
+    /// Track peak, linear scale.
+    pub track_peak: Option<f32>,
+    /// Album gain, in dB.
+    pub album_gain: Option<f32>,
+    /// Album peak, linear scale.
+    pub album_peak: Option<f32>,
+}
+
 #[allow(unused_variables)]
 extern "C" fn seek(datasource: *mut c_void, offset: i64, whence: c_int) -> c_int {
     // TODO permit seeking
@@ -147,35 +299,37 @@ impl<R: Read> VorbisFile<R> {
 
     /// Create a Ogg Vorbis decoder.
     pub fn new(src: R) -> OVResult<VorbisFile<R>> {
-        let mut vf = VorbisFile {
-            src: src,
-            decoder: unsafe { mem::uninitialized() },
-            channels: Vec::new()
-        };
-        let callbacks = ffi::ov_callbacks {
+        VorbisFile::open(src, ffi::ov_callbacks {
             read: VorbisFile::<R>::read,
             seek: seek,
             tell: tell,
             close: close,
+        })
+    }
+
+    /// Shared `ov_open_callbacks` setup for `new` and `new_seekable`.
+    fn open(src: R, callbacks: ffi::ov_callbacks) -> OVResult<VorbisFile<R>> {
+        let mut vf = VorbisFile {
+            src: src,
+            decoder: unsafe { mem::zeroed() },
+            channels: Vec::new(),
+            pcm_buffer: Vec::new()
         };
 
+        // Must be the whole-struct pointer callback_setup() installs later:
+        // a seekable source's seek/tell callbacks can fire during this very
+        // call, before callback_setup() ever runs to fix datasource up.
+        let datasource = &mut vf as *mut VorbisFile<R> as *mut c_void;
         let status = unsafe {
-            ffi::ov_open_callbacks(&mut vf.src as *mut _ as *mut c_void, 
-                                   &mut vf.decoder,
+            ffi::ov_open_callbacks(datasource, &mut vf.decoder,
                                    ptr::null_mut(), 0, callbacks)
         };
 
         match status {
             0 => Ok(vf),
-            f => {
-                // Must not run the destructor. decoder is still uninitialized.
-                // XXX if VorbisFile's Drop impl does more than freeing self.decoder,
-                // this must also be updated.
-                unsafe {
-                    mem::forget(vf);
-                }
-                Err(OVError::from_native(f))
-            }
+            // vf.decoder is zeroed, libvorbisfile's own "empty" state, so it's
+            // safe to let vf drop normally and run ov_clear on it here.
+            f => Err(OVError::from_native(f))
         }
     }
 
@@ -229,6 +383,68 @@ impl<R: Read> VorbisFile<R> {
         })
     }
 
+    /// Gets structured info for the specified bitstream.
+    ///
+    /// For nonseekable streams, returns info for the current bitstream.
+    /// Otherwise, specify bitstream -1 to get the current bitstream, or a
+    /// link index to query a specific link in a seekable multi-link file.
+    pub fn info(&mut self, link: isize) -> Option<StreamInfo> {
+        let vi = unsafe {
+            match ffi::ov_info(&mut self.decoder, link as c_int).as_ref() {
+                Some(vi) => vi,
+                None => return None
+            }
+        };
+        Some(StreamInfo {
+            version: vi.version as i32,
+            channels: vi.channels as i32,
+            rate: vi.rate as i64,
+            bitrate_upper: vi.bitrate_upper as i64,
+            bitrate_nominal: vi.bitrate_nominal as i64,
+            bitrate_lower: vi.bitrate_lower as i64,
+        })
+    }
+
+    /// Total number of PCM samples in the given link, or the whole stream
+    /// if `link` is -1.
+    pub fn total_samples(&mut self, link: isize) -> OVResult<i64> {
+        self.callback_setup();
+        match unsafe { ffi::ov_pcm_total(&mut self.decoder, link as c_int) } {
+            n if n < 0 => Err(OVError::from_native(n as c_int)),
+            n => Ok(n)
+        }
+    }
+
+    /// Total playback duration in seconds of the given link, or the whole
+    /// stream if `link` is -1.
+    pub fn total_duration(&mut self, link: isize) -> OVResult<f64> {
+        self.callback_setup();
+        match unsafe { ffi::ov_time_total(&mut self.decoder, link as c_int) } {
+            n if n < 0.0 => Err(OVError::from_native(n as c_int)),
+            n => Ok(n as f64)
+        }
+    }
+
+    /// Average bitrate in bits per second of the given link, or the whole
+    /// stream if `link` is -1.
+    pub fn bitrate(&mut self, link: isize) -> OVResult<i64> {
+        self.callback_setup();
+        match unsafe { ffi::ov_bitrate(&mut self.decoder, link as c_int) } {
+            n if n < 0 => Err(OVError::from_native(n as c_int)),
+            n => Ok(n as i64)
+        }
+    }
+
+    /// Instantaneous bitrate in bits per second of the most recently
+    /// decoded block.
+    pub fn bitrate_instant(&mut self) -> OVResult<i64> {
+        self.callback_setup();
+        match unsafe { ffi::ov_bitrate_instant(&mut self.decoder) } {
+            n if n < 0 => Err(OVError::from_native(n as c_int)),
+            n => Ok(n as i64)
+        }
+    }
+
     /// Decode a block of samples.
     ///
     /// The emitted values are a slice of channels, each containing an equal
@@ -237,10 +453,10 @@ impl<R: Read> VorbisFile<R> {
         let max_samples = 4096;
         self.callback_setup();
         let mut sample_buffer: *mut *mut f32 = unsafe {
-            mem::uninitialized()
+            mem::zeroed()
         };
         let mut bitstream_idx: c_int = unsafe {
-            mem::uninitialized()
+            mem::zeroed()
         };
         
         let n_samples = unsafe {
@@ -275,6 +491,56 @@ impl<R: Read> VorbisFile<R> {
         })
     }
 
+    /// Decode a block of samples as interleaved PCM bytes.
+    ///
+    /// Unlike `decode`, output is converted to the requested integer sample
+    /// format rather than left as float, which is what most PCM sinks
+    /// (ALSA, MPD's output path) want. The returned slice borrows an
+    /// internal buffer that is reused on the next call to `decode_pcm`.
+    pub fn decode_pcm<'a>(&'a mut self, fmt: PcmFormat) -> OVResult<&'a [u8]> {
+        let capacity = 4096;
+        if self.pcm_buffer.len() != capacity {
+            self.pcm_buffer = vec![0u8; capacity];
+        }
+        self.callback_setup();
+        let mut bitstream_idx: c_int = unsafe {
+            mem::zeroed()
+        };
+
+        let n_bytes = unsafe {
+            match ffi::ov_read(&mut self.decoder, self.pcm_buffer.as_mut_ptr() as *mut c_char,
+                               capacity as c_int, fmt.bigendian as c_int, fmt.word as c_int,
+                               fmt.signed as c_int, &mut bitstream_idx) {
+                0 => {
+                    return Err(OVError::EndOfStream);
+                }
+                x if x < 0 => {
+                    return Err(OVError::from_native(x as c_int));
+                }
+                x => x
+            }
+        };
+
+        Ok(&self.pcm_buffer[..n_bytes as usize])
+    }
+
+    /// Iterate over decoded blocks, borrowing this decoder.
+    ///
+    /// Unlike `decode`, each item owns its samples, so the iterator isn't
+    /// tied to the borrow of the previous item. Iteration stops cleanly
+    /// when the stream ends; other errors are yielded rather than stopping
+    /// iteration.
+    pub fn packets<'a>(&'a mut self) -> Packets<'a, R> {
+        Packets { vf: self }
+    }
+
+    /// Iterate over decoded blocks, consuming this decoder.
+    ///
+    /// See `packets` for iteration semantics.
+    pub fn into_packets(self) -> IntoPackets<R> {
+        IntoPackets { vf: self }
+    }
+
     /// Read `nmemb` items into `ptr` of `size` bytes each.
     /// 
     /// If 0 is returned, error status is implied by errno. If nonzero, there was
@@ -311,6 +577,116 @@ impl<R: Read> VorbisFile<R> {
     }
 }
 
+impl<R: Read + Seek> VorbisFile<R> {
+    /// Create an Ogg Vorbis decoder backed by a seekable source.
+    ///
+    /// Unlike `new`, this installs real `seek`/`tell` callbacks so
+    /// libvorbisfile treats the stream as seekable, enabling `seek_pcm`,
+    /// `seek_time`, and random access into later bitstream links.
+    pub fn new_seekable(src: R) -> OVResult<VorbisFile<R>> {
+        VorbisFile::open(src, ffi::ov_callbacks {
+            read: VorbisFile::<R>::read,
+            seek: VorbisFile::<R>::seek,
+            tell: VorbisFile::<R>::tell,
+            close: close,
+        })
+    }
+
+    /// Whether the underlying stream supports seeking.
+    pub fn is_seekable(&mut self) -> bool {
+        self.callback_setup();
+        unsafe { ffi::ov_seekable(&mut self.decoder) != 0 }
+    }
+
+    /// Seek to the given PCM sample offset.
+    pub fn seek_pcm(&mut self, sample: i64) -> OVResult<()> {
+        self.callback_setup();
+        match unsafe { ffi::ov_pcm_seek(&mut self.decoder, sample) } {
+            0 => Ok(()),
+            e => Err(OVError::from_native(e))
+        }
+    }
+
+    /// Seek to the given time offset, in seconds.
+    pub fn seek_time(&mut self, seconds: f64) -> OVResult<()> {
+        self.callback_setup();
+        match unsafe { ffi::ov_time_seek(&mut self.decoder, seconds as c_double) } {
+            0 => Ok(()),
+            e => Err(OVError::from_native(e))
+        }
+    }
+
+    /// Seek the source, mapping the C `whence` convention onto `SeekFrom`.
+    extern "C" fn seek(datasource: *mut c_void, offset: i64, whence: c_int) -> c_int {
+        let vf: *mut VorbisFile<R> = unsafe { mem::transmute(datasource) };
+        let pos = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return -1
+        };
+        match unsafe { (*vf).src.seek(pos) } {
+            Ok(_) => 0,
+            Err(_) => -1
+        }
+    }
+
+    /// Report the source's current position.
+    extern "C" fn tell(datasource: *mut c_void) -> c_long {
+        let vf: *mut VorbisFile<R> = unsafe { mem::transmute(datasource) };
+        match unsafe { (*vf).src.seek(SeekFrom::Current(0)) } {
+            Ok(pos) => pos as c_long,
+            Err(_) => -1
+        }
+    }
+}
+
+/// An owned block of decoded samples, one `Vec` per channel.
+pub struct DecodedBlock {
+    /// Decoded samples, one `Vec` per channel.
+    pub channels: Vec<Vec<f32>>,
+}
+
+fn next_block<R: Read>(vf: &mut VorbisFile<R>) -> Option<OVResult<DecodedBlock>> {
+    match vf.decode() {
+        Ok(channels) => Some(Ok(DecodedBlock {
+            channels: channels.iter().map(|c| c.to_vec()).collect()
+        })),
+        Err(OVError::EndOfStream) => None,
+        Err(e) => Some(Err(e))
+    }
+}
+
+/// Iterator over decoded blocks, borrowing a `VorbisFile`.
+///
+/// See `VorbisFile::packets`.
+pub struct Packets<'a, R: Read + 'a> {
+    vf: &'a mut VorbisFile<R>,
+}
+
+impl<'a, R: Read> Iterator for Packets<'a, R> {
+    type Item = OVResult<DecodedBlock>;
+
+    fn next(&mut self) -> Option<OVResult<DecodedBlock>> {
+        next_block(self.vf)
+    }
+}
+
+/// Iterator over decoded blocks, owning a `VorbisFile`.
+///
+/// See `VorbisFile::into_packets`.
+pub struct IntoPackets<R: Read> {
+    vf: VorbisFile<R>,
+}
+
+impl<R: Read> Iterator for IntoPackets<R> {
+    type Item = OVResult<DecodedBlock>;
+
+    fn next(&mut self) -> Option<OVResult<DecodedBlock>> {
+        next_block(&mut self.vf)
+    }
+}
+
 impl<R: Read> Drop for VorbisFile<R> {
     fn drop(&mut self) {
         self.callback_setup();
@@ -319,3 +695,50 @@ impl<R: Read> Drop for VorbisFile<R> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{PcmFormat, Tags};
+
+    #[test]
+    fn tags_get_is_case_insensitive() {
+        let tags = Tags::new(&["Title=Song", "ARTIST=Someone"]);
+        assert_eq!(tags.get("title"), Some("Song"));
+        assert_eq!(tags.title(), Some("Song"));
+        assert_eq!(tags.artist(), Some("Someone"));
+        assert_eq!(tags.album(), None);
+    }
+
+    #[test]
+    fn tags_get_all_returns_repeated_keys() {
+        let tags = Tags::new(&["GENRE=Rock", "GENRE=Metal"]);
+        assert_eq!(tags.get_all("genre"), vec!["Rock", "Metal"]);
+    }
+
+    #[test]
+    fn track_number_parses_bare_and_n_of_total_forms() {
+        assert_eq!(Tags::new(&["TRACKNUMBER=3"]).track_number(), Some(3));
+        assert_eq!(Tags::new(&["TRACKNUMBER=3/12"]).track_number(), Some(3));
+        assert_eq!(Tags::new(&["TRACKNUMBER=garbage"]).track_number(), None);
+    }
+
+    #[test]
+    fn replay_gain_parses_db_and_peak_values() {
+        let tags = Tags::new(&[
+            "REPLAYGAIN_TRACK_GAIN=-7.89 dB",
+            "REPLAYGAIN_TRACK_PEAK=0.987654",
+        ]);
+        let rg = tags.replay_gain();
+        assert_eq!(rg.track_gain, Some(-7.89));
+        assert_eq!(rg.track_peak, Some(0.987654));
+        assert_eq!(rg.album_gain, None);
+        assert_eq!(rg.album_peak, None);
+    }
+
+    #[test]
+    fn pcm_format_s16_native_is_signed_two_bytes() {
+        let fmt = PcmFormat::s16_native();
+        assert_eq!(fmt.word, 2);
+        assert!(fmt.signed);
+    }
+}